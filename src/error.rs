@@ -13,6 +13,12 @@ pub enum Error {
   OutOfMemory,
   /// A memory query failed.
   RegionFailure(region::Error),
+  /// A page's protection could not be changed because another allocation
+  /// still shares it in the mode being transitioned away from.
+  PageInUse,
+  /// A read was requested over bytes that were never written, while
+  /// initialization tracking was enabled for the allocation.
+  UninitializedRead,
 }
 
 impl fmt::Display for Error {
@@ -20,6 +26,8 @@ impl fmt::Display for Error {
     match self {
       Error::OutOfMemory => write!(f, "Cannot allocate memory"),
       Error::RegionFailure(ref error) => write!(f, "{}", error),
+      Error::PageInUse => write!(f, "Cannot change page protection: page is shared"),
+      Error::UninitializedRead => write!(f, "Read of uninitialized bytes"),
     }
   }
 }
@@ -0,0 +1,94 @@
+//! Optional tracking of which bytes within a `ProximityBox` have been
+//! written, so trampolines assembled incrementally can be checked for
+//! uninitialized (stale mmap) bytes before being treated as code.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Tracks which bytes of a fixed-length allocation have been written.
+///
+/// Stored as a run-length map from a run's start offset to whether it is
+/// initialized, with adjacent runs of the same state merged together, so
+/// the map stays compact regardless of how many small writes built it up.
+#[derive(Default)]
+pub struct InitMask {
+  runs: BTreeMap<usize, bool>,
+  len: usize,
+}
+
+impl InitMask {
+  /// Creates a mask covering `len` bytes, all initially uninitialized.
+  pub fn new(len: usize) -> Self {
+    let mut runs = BTreeMap::new();
+    runs.insert(0, false);
+    InitMask { runs, len }
+  }
+
+  /// Marks `range` as initialized.
+  pub fn mark_initialized(&mut self, range: Range<usize>) {
+    assert!(range.end <= self.len);
+    if range.start >= range.end {
+      return;
+    }
+
+    // The state just past `range.end` needs to survive the run this
+    // insert creates, so capture it before touching the map.
+    let tail = self.state_at(range.end);
+
+    let superseded = self
+      .runs
+      .range(range.start..range.end)
+      .map(|(&start, _)| start)
+      .collect::<Vec<_>>();
+    for start in superseded {
+      self.runs.remove(&start);
+    }
+
+    self.runs.insert(range.start, true);
+    if range.end < self.len {
+      self.runs.entry(range.end).or_insert(tail);
+    }
+
+    self.merge_adjacent();
+  }
+
+  /// Returns whether every byte in `range` is initialized.
+  pub fn is_initialized(&self, range: Range<usize>) -> bool {
+    let mut pos = range.start;
+    while pos < range.end {
+      if !self.state_at(pos) {
+        return false;
+      }
+      pos = self
+        .runs
+        .range((pos + 1)..)
+        .next()
+        .map(|(&start, _)| start)
+        .unwrap_or(self.len);
+    }
+    true
+  }
+
+  /// Returns the initialization state in effect at `pos`.
+  fn state_at(&self, pos: usize) -> bool {
+    if pos >= self.len {
+      return false;
+    }
+    self.runs.range(..=pos).next_back().map_or(false, |(_, &value)| value)
+  }
+
+  /// Merges consecutive runs sharing the same state, keeping the map's size
+  /// proportional to the number of initialized/uninitialized spans rather
+  /// than the number of writes that produced them.
+  fn merge_adjacent(&mut self) {
+    let mut merged = BTreeMap::new();
+    let mut last_value = None;
+    for (&start, &value) in &self.runs {
+      if Some(value) != last_value {
+        merged.insert(start, value);
+        last_value = Some(value);
+      }
+    }
+    self.runs = merged;
+  }
+}
@@ -20,31 +20,74 @@ use tap::TapOptionOps;
 // TODO: Support small margins/ranges (i.e less than PAGE_SIZE)
 
 mod error;
+mod init;
 mod margin;
+mod protect;
 mod search;
 mod util;
 
 /// A thread-safe memory allocator based on proximity.
 pub struct ProximityAllocator {
-  // TODO: Actually use a range as key
-  pools: RwLock<BTreeMap<usize, Weak<SlicePool<u8>>>>,
+  // Keyed by a pool's start address, so overlap queries can walk the map in
+  // address order; the value carries the pool's full address range so an
+  // overlap can be confirmed without re-deriving it from the pool itself.
+  pools: RwLock<BTreeMap<usize, (Range<usize>, Weak<Pool>)>>,
   options: Vec<MapOption>,
+  guard_pages: bool,
+}
+
+/// A pool together with the per-page write/execute bookkeeping needed for
+/// the W^X allocation workflow (see [`ProximityBox::make_executable`]).
+struct Pool {
+  slab: SlicePool<u8>,
+  pages: protect::PageTracker,
+}
+
+impl Pool {
+  fn new(slab: SlicePool<u8>) -> Self {
+    Pool {
+      slab,
+      pages: protect::PageTracker::new(),
+    }
+  }
+}
+
+impl Deref for Pool {
+  type Target = SlicePool<u8>;
+
+  fn deref(&self) -> &SlicePool<u8> {
+    &self.slab
+  }
 }
 
 impl ProximityAllocator {
   /// Constructs a new allocator with read/write/execute properties.
   pub fn new() -> Self {
-    Self::with_options(vec![
-      MapOption::MapReadable,
-      MapOption::MapWritable,
-      MapOption::MapExecutable,
-    ])
+    Self::with_options(
+      vec![
+        MapOption::MapReadable,
+        MapOption::MapWritable,
+        MapOption::MapExecutable,
+      ],
+      false,
+    )
   }
 
   /// Constructs a new memory pool with the specified options.
-  pub fn with_options(options: Vec<MapOption>) -> Self {
+  ///
+  /// When `guard_pages` is set, every reserved memory map is surrounded by a
+  /// leading and trailing page with no access permissions, so an access that
+  /// runs past either end of a pool's mapping faults instead of reaching an
+  /// adjacent mapping. An overrun from one allocation into another of the
+  /// same pool, or into the unused rest of the pool's last page, does not
+  /// fault.
+  pub fn with_options(options: Vec<MapOption>, guard_pages: bool) -> Self {
     let pools = RwLock::new(BTreeMap::new());
-    ProximityAllocator { pools, options }
+    ProximityAllocator {
+      pools,
+      options,
+      guard_pages,
+    }
   }
 
   /// Allocates memory regardless of proximity.
@@ -53,14 +96,24 @@ impl ProximityAllocator {
   ///
   /// - Panics if the size zero.
   pub fn alloc(&self, size: usize) -> Result<ProximityBox> {
+    self.alloc_with_alignment(size, 1)
+  }
+
+  /// Allocates memory regardless of proximity, aligned to `align` bytes.
+  ///
+  /// # Panics
+  ///
+  /// - Panics if the size is zero.
+  /// - Panics if `align` is not a power of two.
+  pub fn alloc_with_alignment(&self, size: usize, align: usize) -> Result<ProximityBox> {
     assert!(size > 0);
+    assert!(align.is_power_of_two());
     let range = 0..usize::max_value();
     self
-      .alloc_with_existing_pool(size, range.clone())
+      .alloc_with_existing_pool(size, align, range.clone())
       .or_else(|_| {
-        println!("ALLOCING WITH NEW");
-        let pool = self.reserve_memory_map(size, None)?;
-        self.alloc_with_new_pool(pool, size, range)
+        let pool = self.reserve_memory_map(Self::reservation_for(size, align), None)?;
+        self.alloc_with_new_pool(pool, size, align, range)
       })
   }
 
@@ -75,7 +128,23 @@ impl ProximityAllocator {
     origin: *const (),
     margin: impl Margin,
   ) -> Result<ProximityBox> {
-    self.alloc_with_range(size, origin, margin.as_range(origin))
+    self.alloc_with_margin_and_alignment(size, origin, margin, 1)
+  }
+
+  /// Allocates proximity memory within a margin, aligned to `align` bytes.
+  ///
+  /// # Panics
+  ///
+  /// - Panics if the size is zero.
+  /// - Panics if `align` is not a power of two.
+  pub fn alloc_with_margin_and_alignment(
+    &self,
+    size: usize,
+    origin: *const (),
+    margin: impl Margin,
+    align: usize,
+  ) -> Result<ProximityBox> {
+    self.alloc_with_range_and_alignment(size, origin, margin.as_range(origin), align)
   }
 
   /// Allocates proximity memory within an address range.
@@ -89,27 +158,85 @@ impl ProximityAllocator {
     size: usize,
     origin: *const (),
     range: Range<usize>,
+  ) -> Result<ProximityBox> {
+    self.alloc_with_range_and_alignment(size, origin, range, 1)
+  }
+
+  /// Allocates proximity memory within an address range, aligned to `align`
+  /// bytes.
+  ///
+  /// # Panics
+  ///
+  /// - Panics if the size is zero.
+  /// - Panics if the address `range` does not contain `origin`.
+  /// - Panics if `align` is not a power of two.
+  pub fn alloc_with_range_and_alignment(
+    &self,
+    size: usize,
+    origin: *const (),
+    range: Range<usize>,
+    align: usize,
   ) -> Result<ProximityBox> {
     assert!(size > 0);
     assert!(range.contains_(origin as usize));
+    assert!(align.is_power_of_two());
     self
-      .alloc_with_existing_pool(size, range.clone())
+      .alloc_with_existing_pool(size, align, range.clone())
       .or_else(|_| {
-        let pool = self.reserve_memory_map_within_range(size, origin, range.clone())?;
-        self.alloc_with_new_pool(pool, size, range)
+        let reservation = Self::reservation_for(size, align);
+        let pool = self.reserve_memory_map_within_range(reservation, origin, range.clone())?;
+        self.alloc_with_new_pool(pool, size, align, range)
       })
   }
 
-  fn alloc_with_existing_pool(&self, size: usize, range: Range<usize>) -> Result<ProximityBox> {
+  /// Returns how many bytes must be drawn from a `SlicePool` to be able to
+  /// carve out an `align`-aligned window of `size` bytes from it.
+  fn reservation_for(size: usize, align: usize) -> usize {
+    if align <= 1 {
+      size
+    } else {
+      size + align - 1
+    }
+  }
+
+  fn alloc_with_existing_pool(
+    &self,
+    size: usize,
+    align: usize,
+    range: Range<usize>,
+  ) -> Result<ProximityBox> {
+    let reservation = Self::reservation_for(size, align);
     let mut inactive_pools = Vec::new();
     let allocation = {
       let pools = self.pools.read().expect("poisoned lock");
-      let pools_within_range = pools.range(range);
-      pools_within_range
-        .filter_map(|(key, pool)| {
+      // Pools are keyed by start address, so every pool overlapping `range`
+      // has a start before `range.end`; entries starting at or after it
+      // cannot possibly overlap and are excluded by the range query itself.
+      let candidates = pools
+        .range(..range.end)
+        .filter(|(_, (pool_range, _))| pool_range.end > range.start);
+
+      candidates
+        .filter_map(|(key, (_, pool))| {
           let pool = pool.upgrade().tap_none(|| inactive_pools.push(*key))?;
-          let data = pool.alloc(size)?; // TODO: Validate range within pool
-          Some(ProximityBox { pool, data })
+          let data = pool.alloc(reservation)?;
+          let (data, offset) = align_within_range(data, &range, size, align)?;
+          let pages = protect::pages_spanning(data.as_ptr() as usize + offset, size);
+          // A page flipped to read-execute by another live box is not
+          // actually writable; reject this candidate rather than handing
+          // out a box that reports itself writable and segfaults on the
+          // first write.
+          if !pool.pages.try_register_writable(&pages) {
+            return None;
+          }
+          Some(ProximityBox {
+            pool,
+            data,
+            offset,
+            len: size,
+            protection: ::std::cell::Cell::new(protect::BoxProtection::Writable),
+            init: None,
+          })
         }).next()
     };
 
@@ -127,20 +254,36 @@ impl ProximityAllocator {
     &self,
     pool: SlicePool<u8>,
     size: usize,
-    _: Range<usize>,
+    align: usize,
+    range: Range<usize>,
   ) -> Result<ProximityBox> {
-    // TODO: Validate range within pool
-    let data = pool.alloc(size).ok_or(Error::OutOfMemory)?;
-    let pool = Arc::new(pool);
+    let reservation = Self::reservation_for(size, align);
+    let data = pool.alloc(reservation).ok_or(Error::OutOfMemory)?;
+    let (data, offset) = align_within_range(data, &range, size, align).ok_or(Error::OutOfMemory)?;
+    let pool = Arc::new(Pool::new(pool));
+    let pages = protect::pages_spanning(data.as_ptr() as usize + offset, size);
+    // A brand-new pool has no pages registered at all yet, so this can
+    // never observe a live executor; the check is kept, failing the
+    // allocation, rather than assuming that invariant here.
+    if !pool.pages.try_register_writable(&pages) {
+      return Err(Error::OutOfMemory);
+    }
 
-    // TODO: THIS!
-    let range = pool.as_ptr() as usize/*..pool.len()*/;
+    let start = pool.as_ptr() as usize;
+    let pool_range = start..start + pool.len();
     self
       .pools
       .write()
       .expect("poisoned lock")
-      .insert(range, Arc::downgrade(&pool));
-    Ok(ProximityBox { pool, data })
+      .insert(start, (pool_range, Arc::downgrade(&pool)));
+    Ok(ProximityBox {
+      pool,
+      data,
+      offset,
+      len: size,
+      protection: ::std::cell::Cell::new(protect::BoxProtection::Writable),
+      init: None,
+    })
   }
 
   /// Reserves a memory map within a range.
@@ -150,11 +293,15 @@ impl ProximityAllocator {
     origin: *const (),
     range: Range<usize>,
   ) -> Result<SlicePool<u8>> {
+    // A candidate address must have room for the guard pages as well, or the
+    // fixed-address reservation below would spill outside the free region.
+    let required_size = self.reservation_size(size);
+
     iter::empty()
       // Search for a free region after & before the origin
-      .chain(search::free_regions_after(origin, range.clone()))
+      .chain(search::free_regions_after(origin, range.clone(), required_size))
       // TODO: Useless on macOS
-      .chain(search::free_regions_before(origin, range.clone()))
+      .chain(search::free_regions_before(origin, range.clone(), required_size))
       // Attempt to allocate a pool for each free region
       .filter_map(|result| {
         match result {
@@ -168,8 +315,27 @@ impl ProximityAllocator {
       .unwrap_or(Err(Error::OutOfMemory))
   }
 
+  /// Returns the footprint a reservation of `size` bytes actually needs,
+  /// accounting for the leading and trailing guard page when enabled.
+  ///
+  /// The interior is rounded up to a whole number of pages so the trailing
+  /// guard page always starts on a page boundary, regardless of `size`.
+  fn reservation_size(&self, size: usize) -> usize {
+    if self.guard_pages {
+      let page_size = region::page::size();
+      let interior = (size + page_size - 1) / page_size * page_size;
+      interior + 2 * page_size
+    } else {
+      size
+    }
+  }
+
   /// Reserves a memory map at an optional fixed address.
+  ///
+  /// `address`, when given, is the start of the full reservation (including
+  /// any guard pages), as produced by the free-region search above.
   fn reserve_memory_map(&self, size: usize, address: Option<*const ()>) -> Result<SlicePool<u8>> {
+    let mapped_size = self.reservation_size(size);
     let map = if let Some(address) = address {
       let options = self
         .options
@@ -177,20 +343,161 @@ impl ProximityAllocator {
         .cloned()
         .chain([MapOption::MapAddr(address as *const _)].iter().cloned())
         .collect::<Vec<_>>();
-      MemoryMap::new(size, &options)
+      MemoryMap::new(mapped_size, &options)
     } else {
-      MemoryMap::new(size, &self.options)
+      MemoryMap::new(mapped_size, &self.options)
     }.map_err(|_| Error::OutOfMemory)?;
 
-    Ok(SlicePool::new(util::SliceableMemoryMap::new(map)))
+    if !self.guard_pages {
+      return Ok(SlicePool::new(util::SliceableMemoryMap::new(map)));
+    }
+
+    let page_size = region::page::size();
+    let base = map.data() as usize;
+    unsafe {
+      region::protect(base as *const u8, page_size, region::Protection::NONE)?;
+      region::protect(
+        (base + map.len() - page_size) as *const u8,
+        page_size,
+        region::Protection::NONE,
+      )?;
+    }
+
+    Ok(SlicePool::new(util::SliceableMemoryMap::with_guard_pages(
+      map, page_size, size,
+    )))
+  }
+}
+
+/// Carves an `align`-aligned, `size`-byte window out of `data` and returns
+/// it together with its offset into `data`, provided that window lies
+/// entirely within `range`. Otherwise drops `data` so the memory is returned
+/// to its pool and the caller can try another candidate.
+fn align_within_range(
+  data: SliceBox<u8>,
+  range: &Range<usize>,
+  size: usize,
+  align: usize,
+) -> Option<(SliceBox<u8>, usize)> {
+  let start = data.as_ptr() as usize;
+  let aligned_start = (start + align - 1) & !(align - 1);
+  let offset = aligned_start - start;
+  let end = aligned_start + size;
+
+  if range.contains_(aligned_start) && end <= range.end {
+    Some((data, offset))
+  } else {
+    None
   }
 }
 
 /// A slice for allocated proximity memory.
 pub struct ProximityBox {
-  #[allow(unused)]
-  pool: Arc<SlicePool<u8>>,
+  pool: Arc<Pool>,
   data: SliceBox<u8>,
+  offset: usize,
+  len: usize,
+  protection: ::std::cell::Cell<protect::BoxProtection>,
+  init: Option<::std::cell::RefCell<init::InitMask>>,
+}
+
+impl ProximityBox {
+  /// Returns the page-aligned addresses this box's bytes span.
+  fn pages(&self) -> Vec<usize> {
+    protect::pages_spanning(self.data.as_ptr() as usize + self.offset, self.len)
+  }
+
+  /// Enables tracking of which bytes of this allocation have been written,
+  /// so `assert_initialized`/`try_as_code` can catch reads of stale (never
+  /// written) bytes. Opt-in, to avoid the bookkeeping cost on allocations
+  /// that don't need it.
+  pub fn track_initialization(&mut self) {
+    self.init = Some(::std::cell::RefCell::new(init::InitMask::new(self.len)));
+  }
+
+  /// Writes `data` at `offset` into this allocation, marking those bytes as
+  /// initialized if `track_initialization` was called.
+  ///
+  /// # Panics
+  ///
+  /// - Panics if `data` does not fit at `offset`.
+  pub fn write_at(&mut self, offset: usize, data: &[u8]) {
+    let end = offset + data.len();
+    self.deref_mut()[offset..end].copy_from_slice(data);
+    if let Some(init) = &self.init {
+      init.borrow_mut().mark_initialized(offset..end);
+    }
+  }
+
+  /// Panics if any byte in `range` was never written via `write_at`.
+  ///
+  /// Only checks that `range` is in bounds if `track_initialization` was
+  /// never called, since then no initialization state is being kept.
+  ///
+  /// # Panics
+  ///
+  /// - Panics if `range` is out of bounds or contains an uninitialized byte.
+  pub fn assert_initialized(&self, range: Range<usize>) {
+    assert!(range.end <= self.len);
+    if let Some(init) = &self.init {
+      assert!(
+        init.borrow().is_initialized(range),
+        "read of uninitialized bytes"
+      );
+    }
+  }
+
+  /// Returns this allocation's bytes for use as code, failing if
+  /// initialization tracking is enabled and any byte was never written —
+  /// a likely sign of stale mmap bytes rather than assembled trampoline
+  /// code.
+  pub fn try_as_code(&self) -> Result<&[u8]> {
+    if let Some(init) = &self.init {
+      if !init.borrow().is_initialized(0..self.len) {
+        return Err(Error::UninitializedRead);
+      }
+    }
+    Ok(self.deref())
+  }
+
+  /// Transitions this allocation's pages from writable to read-execute, so
+  /// the trampoline bytes just written into it can be safely run.
+  ///
+  /// Protection changes apply to every page the box spans, not just the
+  /// bytes it occupies, since other allocations may share those pages.
+  ///
+  /// # Errors
+  ///
+  /// Fails if another live `ProximityBox` still shares one of these pages
+  /// and claims write access to it; flipping would otherwise silently
+  /// revoke that box's write permission.
+  pub fn make_executable(&mut self) -> Result<()> {
+    if let protect::BoxProtection::Executable = self.protection.get() {
+      return Ok(());
+    }
+
+    self.pool.pages.make_executable(&self.pages())?;
+    self.protection.set(protect::BoxProtection::Executable);
+    Ok(())
+  }
+
+  /// Transitions this allocation's pages back from read-execute to
+  /// writable.
+  ///
+  /// # Errors
+  ///
+  /// Fails if another live `ProximityBox` still shares one of these pages
+  /// and claims execute access to it; see
+  /// [`make_executable`](#method.make_executable).
+  pub fn make_writable(&mut self) -> Result<()> {
+    if let protect::BoxProtection::Writable = self.protection.get() {
+      return Ok(());
+    }
+
+    self.pool.pages.make_writable(&self.pages())?;
+    self.protection.set(protect::BoxProtection::Writable);
+    Ok(())
+  }
 }
 
 impl fmt::Debug for ProximityBox {
@@ -203,13 +510,19 @@ impl Deref for ProximityBox {
   type Target = [u8];
 
   fn deref(&self) -> &Self::Target {
-    self.data.deref()
+    &self.data.deref()[self.offset..self.offset + self.len]
   }
 }
 
 impl DerefMut for ProximityBox {
   fn deref_mut(&mut self) -> &mut [u8] {
-    self.data.deref_mut()
+    &mut self.data.deref_mut()[self.offset..self.offset + self.len]
+  }
+}
+
+impl Drop for ProximityBox {
+  fn drop(&mut self) {
+    self.pool.pages.release(&self.pages(), self.protection.get());
   }
 }
 
@@ -229,6 +542,39 @@ mod tests {
     assert!(distance <= DISTANCE);
   }
 
+  #[test]
+  fn test_alignment() {
+    let allocator = ProximityAllocator::new();
+    let slice = allocator.alloc_with_alignment(0x10, 0x1000).unwrap();
+    assert_eq!(slice.as_ptr() as usize % 0x1000, 0);
+    assert_eq!(slice.len(), 0x10);
+  }
+
+  #[test]
+  fn test_make_executable() {
+    let allocator = ProximityAllocator::with_options(
+      vec![MapOption::MapReadable, MapOption::MapWritable],
+      false,
+    );
+    let mut slice = allocator.alloc(0x10).unwrap();
+    slice[0] = 0xC3;
+    slice.make_executable().unwrap();
+    slice.make_writable().unwrap();
+  }
+
+  #[test]
+  fn test_initialization_tracking() {
+    let allocator = ProximityAllocator::new();
+    let mut slice = allocator.alloc(0x10).unwrap();
+    slice.track_initialization();
+
+    assert!(slice.try_as_code().is_err());
+
+    slice.write_at(0, &[0xC3; 0x10]);
+    slice.assert_initialized(0..0x10);
+    assert!(slice.try_as_code().is_ok());
+  }
+
   #[test]
   fn test_pool_reuse() {
     let allocator = ProximityAllocator::new();
@@ -248,4 +594,60 @@ mod tests {
     let _slice = allocator.alloc(0x100).unwrap();
     assert_eq!(allocator.pools.read().unwrap().len(), 1);
   }
+
+  #[test]
+  fn test_range_bounded_allocation_rejects_out_of_range_candidates() {
+    let allocator = ProximityAllocator::new();
+    let page_size = region::page::size();
+
+    // A tiny allocation still reserves at least a full page (as demonstrated
+    // by `test_pool_reuse` above), leaving most of it free; a range check
+    // keyed only on the pool's start address, rather than on the bytes
+    // actually handed back, could be fooled into serving the next request
+    // out of that free space regardless of where `range` says it may land.
+    let anchor = allocator.alloc(1).unwrap();
+    let anchor_start = anchor.as_ptr() as usize;
+    let page_end = anchor_start + page_size;
+
+    // A range straddling the tail of that same page and a wide span of free
+    // space past it (the pages immediately adjacent to a mapping are often
+    // occupied by unrelated guard pages, so the span needs to be generous,
+    // matching the margin `test_margin` above relies on): far from where a
+    // bump allocator would place the next slice (right after `anchor`, near
+    // the front), so this can only be satisfied by rejecting that candidate
+    // and falling through to a new pool in the free space beyond the page,
+    // never by escaping into the front.
+    let range = (page_end - 0x10)..(page_end + 0x1_000_000);
+    let origin = page_end as *const ();
+
+    let slice = allocator.alloc_with_range(0x10, origin, range.clone()).unwrap();
+    let start = slice.as_ptr() as usize;
+    assert!(
+      range.contains_(start),
+      "allocation escaped the requested proximity range"
+    );
+  }
+
+  #[test]
+  fn test_guard_pages_allocation_stays_within_interior() {
+    let allocator = ProximityAllocator::with_options(
+      vec![
+        MapOption::MapReadable,
+        MapOption::MapWritable,
+        MapOption::MapExecutable,
+      ],
+      true,
+    );
+
+    let mut slice = allocator.alloc(0x100).unwrap();
+    assert_eq!(slice.len(), 0x100);
+
+    // Every byte must be safely writable; if the guard-page offset
+    // arithmetic let any of these bytes fall on a guard page instead of
+    // the interior, this would segfault instead of completing.
+    for byte in slice.iter_mut() {
+      *byte = 0xCC;
+    }
+    assert!(slice.iter().all(|&byte| byte == 0xCC));
+  }
 }
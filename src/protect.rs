@@ -0,0 +1,151 @@
+//! Per-page write/execute bookkeeping for the W^X allocation workflow.
+//!
+//! Several `ProximityBox`es can share a single OS page (pools hand out
+//! sub-page slices), so a page's protection can only be flipped when every
+//! box claiming it agrees. This tracks, per page, how many live boxes
+//! currently claim write access versus execute access.
+
+use super::error::*;
+use region;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The protection a `ProximityBox` currently claims on its pages.
+#[derive(Clone, Copy, PartialEq)]
+pub enum BoxProtection {
+  /// The box claims write access.
+  Writable,
+  /// The box claims execute access.
+  Executable,
+}
+
+/// Returns the page-aligned addresses spanned by `[start, start + len)`.
+pub fn pages_spanning(start: usize, len: usize) -> Vec<usize> {
+  let page_size = region::page::size();
+  let first_page = start - (start % page_size);
+  let end = start + len;
+
+  let mut pages = Vec::new();
+  let mut page = first_page;
+  while page < end {
+    pages.push(page);
+    page += page_size;
+  }
+  pages
+}
+
+#[derive(Default)]
+struct PageUsers {
+  writers: usize,
+  executors: usize,
+}
+
+/// Tracks the writers/executors sharing each page of a pool.
+#[derive(Default)]
+pub struct PageTracker {
+  pages: Mutex<BTreeMap<usize, PageUsers>>,
+}
+
+impl PageTracker {
+  /// Creates an empty tracker.
+  pub fn new() -> Self {
+    PageTracker::default()
+  }
+
+  /// Registers a freshly allocated box as a writer on every page it spans,
+  /// unless one of them already has a live executor, in which case nothing
+  /// is registered and `false` is returned.
+  ///
+  /// A page that was flipped to read-execute by `make_executable` is not
+  /// writable at the OS level, so a box handed out on such a page must
+  /// never be accepted as a writer: the caller is expected to reject the
+  /// underlying `SlicePool` allocation and try another candidate instead,
+  /// the same way `align_within_range` rejects an out-of-range one.
+  pub fn try_register_writable(&self, pages: &[usize]) -> bool {
+    let mut tracker = self.pages.lock().expect("poisoned lock");
+    if pages
+      .iter()
+      .any(|page| tracker.get(page).map_or(false, |users| users.executors > 0))
+    {
+      return false;
+    }
+
+    for &page in pages {
+      tracker.entry(page).or_insert_with(PageUsers::default).writers += 1;
+    }
+    true
+  }
+
+  /// Transitions `pages` from writable to read-execute.
+  ///
+  /// Fails, without changing any page, if another box still claims write
+  /// access on one of them.
+  pub fn make_executable(&self, pages: &[usize]) -> Result<()> {
+    self.transition(pages, BoxProtection::Executable)
+  }
+
+  /// Transitions `pages` back from read-execute to writable.
+  ///
+  /// Fails, without changing any page, if another box still claims execute
+  /// access on one of them.
+  pub fn make_writable(&self, pages: &[usize]) -> Result<()> {
+    self.transition(pages, BoxProtection::Writable)
+  }
+
+  /// Releases a box's claim on `pages` without touching their protection,
+  /// called when a box is freed.
+  pub fn release(&self, pages: &[usize], protection: BoxProtection) {
+    let mut tracker = self.pages.lock().expect("poisoned lock");
+    for &page in pages {
+      if let Some(users) = tracker.get_mut(&page) {
+        match protection {
+          BoxProtection::Writable => users.writers = users.writers.saturating_sub(1),
+          BoxProtection::Executable => users.executors = users.executors.saturating_sub(1),
+        }
+      }
+    }
+  }
+
+  fn transition(&self, pages: &[usize], to: BoxProtection) -> Result<()> {
+    let mut tracker = self.pages.lock().expect("poisoned lock");
+
+    // Check every page before changing any of them, so a rejection never
+    // leaves the box's span half-flipped.
+    for &page in pages {
+      let users = tracker.entry(page).or_insert_with(PageUsers::default);
+      let blocked = match to {
+        BoxProtection::Executable => users.writers > 1,
+        BoxProtection::Writable => users.executors > 1,
+      };
+      if blocked {
+        return Err(Error::PageInUse);
+      }
+    }
+
+    let page_size = region::page::size();
+    let new_protection = match to {
+      BoxProtection::Executable => region::Protection::READ_EXECUTE,
+      BoxProtection::Writable => region::Protection::READ_WRITE,
+    };
+
+    for &page in pages {
+      unsafe {
+        region::protect(page as *const u8, page_size, new_protection)?;
+      }
+
+      let users = tracker.get_mut(&page).expect("page registered above");
+      match to {
+        BoxProtection::Executable => {
+          users.writers -= 1;
+          users.executors += 1;
+        }
+        BoxProtection::Writable => {
+          users.executors -= 1;
+          users.writers += 1;
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
@@ -1,21 +1,23 @@
-use super::{error::*, util::RangeContains};
+use super::error::*;
 use region;
 use std::ops::Range;
 
-/// Returns an iterator for free after the specified address.
+/// Returns an iterator for free regions after the specified address.
 pub fn free_regions_after(
   origin: *const (),
   range: Range<usize>,
+  size: usize,
 ) -> impl Iterator<Item = Result<*const ()>> {
-  FreeRegionIter::new(origin, range, SearchDirection::After)
+  FreeRegionIter::new(origin, range, size, SearchDirection::After)
 }
 
-/// Returns an iterator for free before the specified address.
+/// Returns an iterator for free regions before the specified address.
 pub fn free_regions_before(
   origin: *const (),
   range: Range<usize>,
+  size: usize,
 ) -> impl Iterator<Item = Result<*const ()>> {
-  FreeRegionIter::new(origin, range, SearchDirection::Before)
+  FreeRegionIter::new(origin, range, size, SearchDirection::Before)
 }
 
 /// Direction for the region search.
@@ -24,20 +26,97 @@ enum SearchDirection {
   After,
 }
 
-/// An iterator searching for free regions.
+/// An iterator over free address candidates, derived from the gaps between
+/// the mapped regions covering an address range. Unlike walking the address
+/// space a page at a time, this costs one query per *mapped region* rather
+/// than one per *page*, so crossing a large reserved span is cheap.
+///
+/// One tradeoff: candidates are yielded one per *gap* rather than one per
+/// *page* within it. If a `MAP_FIXED` placement races with another mapping
+/// and fails, the next candidate skips to an entirely different gap instead
+/// of retrying further along the same one.
 struct FreeRegionIter {
-  range: Range<usize>,
-  search: SearchDirection,
-  current: usize,
+  candidates: ::std::vec::IntoIter<Result<*const ()>>,
 }
 
 impl FreeRegionIter {
   /// Creates a new iterator for free regions.
-  fn new(origin: *const (), range: Range<usize>, search: SearchDirection) -> Self {
+  fn new(origin: *const (), range: Range<usize>, size: usize, search: SearchDirection) -> Self {
+    let origin = origin as usize;
+    let bounds = match search {
+      SearchDirection::After => origin..range.end,
+      SearchDirection::Before => range.start..origin,
+    };
+
+    let candidates = match Self::gaps(bounds) {
+      Ok(gaps) => Self::candidates(gaps, size, &search)
+        .into_iter()
+        .map(Ok)
+        .collect(),
+      Err(error) => vec![Err(error)],
+    };
+
     FreeRegionIter {
-      range,
-      current: origin as usize,
-      search,
+      candidates: candidates.into_iter(),
+    }
+  }
+
+  /// Enumerates the free gaps within `bounds`, ascending by address: the
+  /// span before the first mapped region, the spans between consecutive
+  /// regions, and the span after the last one.
+  fn gaps(bounds: Range<usize>) -> Result<Vec<Range<usize>>> {
+    if bounds.start >= bounds.end {
+      return Ok(Vec::new());
+    }
+
+    let regions = region::query_range(bounds.start as *const u8, bounds.end - bounds.start)?
+      .map(|region| region.map(|region| region.as_range()))
+      .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+    Ok(Self::gaps_from_regions(bounds, regions.into_iter()))
+  }
+
+  /// The pure gap computation behind `gaps`, split out so it can be
+  /// exercised with synthetic regions in tests without touching real memory
+  /// maps. `regions` is assumed sorted and non-overlapping, as `query_range`
+  /// returns them.
+  fn gaps_from_regions(bounds: Range<usize>, regions: impl Iterator<Item = Range<usize>>) -> Vec<Range<usize>> {
+    let mut gaps = Vec::new();
+    let mut cursor = bounds.start;
+
+    for mapped in regions {
+      if mapped.start > cursor {
+        gaps.push(cursor..mapped.start);
+      }
+      cursor = cursor.max(mapped.end);
+    }
+
+    if cursor < bounds.end {
+      gaps.push(cursor..bounds.end);
+    }
+
+    gaps
+  }
+
+  /// Turns gaps into candidate addresses, keeping only those large enough to
+  /// hold `size` bytes, and orders them outward from the search's origin.
+  fn candidates(gaps: Vec<Range<usize>>, size: usize, search: &SearchDirection) -> Vec<*const ()> {
+    let large_enough = gaps.into_iter().filter(|gap| gap.end - gap.start >= size);
+
+    match search {
+      // Ascending gaps are already ordered outward (upward) from the origin;
+      // allocate from the low end of each gap.
+      SearchDirection::After => large_enough.map(|gap| gap.start as *const ()).collect(),
+      // The origin sits at the high end of the search bounds, so the gap
+      // closest to it comes last; allocate from the high end of each gap so
+      // the candidate address stays as close to the origin as possible.
+      SearchDirection::Before => {
+        let mut candidates = large_enough
+          .map(|gap| (gap.end - size) as *const ())
+          .collect::<Vec<_>>();
+        candidates.reverse();
+        candidates
+      }
     }
   }
 }
@@ -45,37 +124,80 @@ impl FreeRegionIter {
 impl Iterator for FreeRegionIter {
   type Item = Result<*const ()>;
 
-  /// Returns the closest free region for the current address.
   fn next(&mut self) -> Option<Self::Item> {
+    self.candidates.next()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mmap::{MapOption, MemoryMap};
+
+  #[test]
+  fn test_gaps_from_regions_surrounds_and_separates_mapped_regions() {
+    let gaps = FreeRegionIter::gaps_from_regions(100..1100, vec![200..300, 500..600].into_iter());
+    assert_eq!(gaps, vec![100..200, 300..500, 600..1100]);
+  }
+
+  #[test]
+  fn test_gaps_from_regions_omits_exhausted_bounds() {
+    // No gap before the first region (it starts exactly at `bounds.start`)
+    // and none after the last (it ends exactly at `bounds.end`).
+    let gaps = FreeRegionIter::gaps_from_regions(100..140, vec![100..110, 110..140].into_iter());
+    assert!(gaps.is_empty());
+  }
+
+  #[test]
+  fn test_candidates_filters_gaps_too_small() {
+    let gaps = vec![100..110, 120..125, 140..200];
+    let candidates = FreeRegionIter::candidates(gaps, 10, &SearchDirection::After);
+    // The 5-byte gap (120..125) cannot hold a 10-byte allocation and is
+    // dropped; the other two, each at least 10 bytes, survive.
+    assert_eq!(candidates, vec![100 as *const (), 140 as *const ()]);
+  }
+
+  #[test]
+  fn test_candidates_after_orders_ascending_from_the_low_end_of_each_gap() {
+    let gaps = vec![100..110, 140..200];
+    let candidates = FreeRegionIter::candidates(gaps, 10, &SearchDirection::After);
+    assert_eq!(candidates, vec![100 as *const (), 140 as *const ()]);
+  }
+
+  #[test]
+  fn test_candidates_before_orders_from_the_high_end_inward_to_the_origin() {
+    let gaps = vec![100..110, 140..200];
+    let candidates = FreeRegionIter::candidates(gaps, 10, &SearchDirection::Before);
+    // `Before` bounds put the origin at the high end, so the gap closest to
+    // it (140..200) must come first, carved from its high end (200 - 10).
+    assert_eq!(candidates, vec![190 as *const (), 100 as *const ()]);
+  }
+
+  #[test]
+  fn test_free_regions_after_finds_real_unmapped_space() {
+    // This exercises `gaps`/`free_regions_after` through their real
+    // `region::query_range` integration, against real memory, so a broken
+    // cast or unhandled query error can't hide behind the pure
+    // `gaps_from_regions`/`candidates` tests above. The search spans a wide
+    // range (matching the margin used by `lib.rs`'s own `test_margin`),
+    // since the pages immediately around any one mapping are often densely
+    // occupied by unrelated guard pages and arenas.
     let page_size = region::page::size();
-    let memory = 1..usize::max_value();
-
-    while memory.contains_(self.current) && self.range.contains_(self.current) {
-      match region::query(self.current as *const _) {
-        Ok(region) => {
-          self.current = match self.search {
-            SearchDirection::Before => region.lower().saturating_sub(page_size),
-            SearchDirection::After => region.upper(),
-          }
-        }
-        Err(error) => {
-          // Check whether the region is free, otherwise return the error
-          let result = Some(match error {
-            region::Error::FreeMemory => Ok(self.current as *const _),
-            inner => Err(Error::RegionFailure(inner)),
-          });
-
-          // Adjust the offset for repeated calls.
-          self.current = match self.search {
-            SearchDirection::Before => self.current.saturating_sub(page_size),
-            SearchDirection::After => self.current.saturating_add(page_size),
-          };
-
-          return result;
-        }
-      }
-    }
+    let map = MemoryMap::new(page_size, &[MapOption::MapReadable]).unwrap();
+    let mapped_start = map.data() as usize;
+    let bounds_end = mapped_start + 0x1_000_000;
+
+    let candidate = free_regions_after(mapped_start as *const (), mapped_start..bounds_end, page_size)
+      .next()
+      .expect("a free region")
+      .expect("no query error");
 
-    None
+    // Whatever candidate comes back must itself be free: fixing a real
+    // mapping there must succeed.
+    let options = [
+      MapOption::MapReadable,
+      MapOption::MapAddr(candidate as *const _),
+    ];
+    assert!(MemoryMap::new(page_size, &options).is_ok());
   }
 }
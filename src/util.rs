@@ -13,19 +13,40 @@ impl<Idx: PartialOrd<Idx>> RangeContains<Idx> for Range<Idx> {
 }
 
 /// A wrapper for making a memory map compatible with `SlicePool`.
-pub struct SliceableMemoryMap(MemoryMap);
+///
+/// `offset`/`len` describe the sliceable window into the map: normally the
+/// whole map, but when the map reserves extra guard pages at each end, only
+/// the interior bytes between them.
+pub struct SliceableMemoryMap {
+  map: MemoryMap,
+  offset: usize,
+  len: usize,
+}
 
 impl SliceableMemoryMap {
   pub fn new(map: MemoryMap) -> Self {
-    SliceableMemoryMap(map)
+    let len = map.len();
+    SliceableMemoryMap {
+      map,
+      offset: 0,
+      len,
+    }
+  }
+
+  /// Wraps a map whose bytes `[0, offset)` are an inaccessible guard page,
+  /// exposing only the `len` bytes that follow them. Bytes from
+  /// `offset + len` onward (a trailing guard page, and any padding before
+  /// it) are not exposed.
+  pub fn with_guard_pages(map: MemoryMap, offset: usize, len: usize) -> Self {
+    SliceableMemoryMap { map, offset, len }
   }
 
   pub fn as_slice(&self) -> &[u8] {
-    unsafe { slice::from_raw_parts(self.0.data(), self.0.len()) }
+    unsafe { slice::from_raw_parts(self.map.data().add(self.offset), self.len) }
   }
 
   pub fn as_mut_slice(&mut self) -> &mut [u8] {
-    unsafe { slice::from_raw_parts_mut(self.0.data(), self.0.len()) }
+    unsafe { slice::from_raw_parts_mut(self.map.data().add(self.offset), self.len) }
   }
 }
 
@@ -43,3 +64,25 @@ impl AsMut<[u8]> for SliceableMemoryMap {
 
 unsafe impl Send for SliceableMemoryMap {}
 unsafe impl Sync for SliceableMemoryMap {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mmap::MapOption;
+  use region;
+
+  #[test]
+  fn test_guard_pages_expose_only_the_interior() {
+    let page_size = region::page::size();
+    let map = MemoryMap::new(
+      page_size * 3,
+      &[MapOption::MapReadable, MapOption::MapWritable],
+    ).unwrap();
+    let base = map.data() as usize;
+
+    let sliceable = SliceableMemoryMap::with_guard_pages(map, page_size, page_size);
+
+    assert_eq!(sliceable.as_slice().len(), page_size);
+    assert_eq!(sliceable.as_slice().as_ptr() as usize, base + page_size);
+  }
+}